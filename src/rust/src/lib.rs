@@ -1,4 +1,6 @@
+use pyo3::exceptions::PyRuntimeError;
 use pyo3::prelude::*;
+use pyo3::types::{PyAny, PyList, PySequence};
 use pyo3::{types::PyString, PyResult, Python};
 
 static NEEDS_SANITIZE: [bool; 256] = {
@@ -11,23 +13,38 @@ static NEEDS_SANITIZE: [bool; 256] = {
     needs_sanitize
 };
 
+const LO_BITS: u64 = 0x0101010101010101;
+const HI_BITS: u64 = 0x8080808080808080;
+const TARGET_BYTES: [u8; 5] = [b'"', b'&', b'\'', b'<', b'>'];
+
+// Classic SWAR zero-byte test: for `diff = word ^ (byte * 0x0101..01)`, any
+// lane equal to `byte` becomes 0x00 in `diff`. Since every target byte is
+// < 0x80, this can't false-positive on the high bit.
+#[inline]
+fn zero_byte_mask(word: u64, target: u8) -> u64 {
+    let diff = word ^ (target as u64 * LO_BITS);
+    diff.wrapping_sub(LO_BITS) & !diff & HI_BITS
+}
+
 pub fn needs_sanitize(bytes: &[u8]) -> Option<usize> {
-    let chunks = bytes.chunks_exact(4);
+    let chunks = bytes.chunks_exact(8);
     let rest = chunks.remainder();
 
     for (i, chunk) in chunks.enumerate() {
-        let a = NEEDS_SANITIZE[chunk[0] as usize];
-        let b = NEEDS_SANITIZE[chunk[1] as usize];
-        let c = NEEDS_SANITIZE[chunk[2] as usize];
-        let d = NEEDS_SANITIZE[chunk[3] as usize];
-        if a | b | c | d {
-            return Some(i * 4);
+        let word = u64::from_le_bytes(chunk.try_into().unwrap());
+        let mut mask = 0u64;
+        for &target in TARGET_BYTES.iter() {
+            mask |= zero_byte_mask(word, target);
+        }
+        if mask != 0 {
+            return Some(i * 8 + (mask.trailing_zeros() / 8) as usize);
         }
     }
 
+    let tail_start = bytes.len() - rest.len();
     for (i, &b) in rest.iter().enumerate() {
         if NEEDS_SANITIZE[b as usize] {
-            return Some(((bytes.len() / 4) * 4) + i);
+            return Some(tail_start + i);
         }
     }
 
@@ -46,31 +63,123 @@ static SANITIZE_INDEX: [i8; 256] = {
 
 static SANITIZED_VALUE: [&str; 5] = ["&#34;", "&amp;", "&#39;", "&lt;", "&gt;"];
 
-pub fn lut_replace(input: &str) -> Option<String> {
+// Scans and replaces in one pass, writing straight into `out` instead of
+// building a full `String` first, so peak memory for very large inputs is
+// bounded by whatever `out` buffers rather than the whole document.
+pub fn escape_to<W: std::fmt::Write>(input: &str, out: &mut W) -> std::fmt::Result {
     let bytes = input.as_bytes();
-    if let Some(mut idx) = needs_sanitize(bytes) {
+    let mut idx = 0;
+    while let Some(rel) = needs_sanitize(&bytes[idx..]) {
+        let found = idx + rel;
+        out.write_str(&input[idx..found])?;
+        let replace_idx = SANITIZE_INDEX[bytes[found] as usize];
+        out.write_str(SANITIZED_VALUE[replace_idx as usize])?;
+        idx = found + 1;
+    }
+    out.write_str(&input[idx..])
+}
+
+pub fn lut_replace(input: &str) -> Option<String> {
+    if needs_sanitize(input.as_bytes()).is_some() {
         let mut out = String::with_capacity(input.len());
-        let mut prev_idx = 0;
-        for &b in bytes[idx..].iter() {
-            let replace_idx = SANITIZE_INDEX[b as usize];
-            if replace_idx >= 0 {
-                if prev_idx < idx {
-                    out.push_str(&input[prev_idx..idx]);
-                }
-                out.push_str(SANITIZED_VALUE[replace_idx as usize]);
-                prev_idx = idx + 1;
-            }
-            idx += 1;
-        }
-        if prev_idx < idx {
-            out.push_str(&input[prev_idx..idx]);
-        }
+        escape_to(input, &mut out).expect("writing to a String never fails");
         Some(out)
     } else {
         None
     }
 }
 
+fn needs_unescape(bytes: &[u8]) -> Option<usize> {
+    let chunks = bytes.chunks_exact(8);
+    let rest = chunks.remainder();
+
+    for (i, chunk) in chunks.enumerate() {
+        let word = u64::from_le_bytes(chunk.try_into().unwrap());
+        let mask = zero_byte_mask(word, b'&');
+        if mask != 0 {
+            return Some(i * 8 + (mask.trailing_zeros() / 8) as usize);
+        }
+    }
+
+    let tail_start = bytes.len() - rest.len();
+    for (i, &b) in rest.iter().enumerate() {
+        if b == b'&' {
+            return Some(tail_start + i);
+        }
+    }
+
+    None
+}
+
+// Parses the entity at the start of `input` (which begins with '&') and
+// returns the decoded char together with the byte length of the whole
+// `&...;` sequence, or `None` if it isn't one of ours / isn't well-formed.
+fn decode_entity(input: &str) -> Option<(char, usize)> {
+    let rest = &input[1..];
+    if rest.starts_with("amp;") {
+        return Some(('&', 5));
+    }
+    if rest.starts_with("lt;") {
+        return Some(('<', 4));
+    }
+    if rest.starts_with("gt;") {
+        return Some(('>', 4));
+    }
+    let digits = rest.strip_prefix('#')?;
+    let (hex, digits) = match digits.strip_prefix('x').or_else(|| digits.strip_prefix('X')) {
+        Some(d) => (true, d),
+        None => (false, digits),
+    };
+    // Bound the lookahead to the widest a valid code point can be (decimal
+    // 1114111, hex 10FFFF) so a malformed `&#...` with no `;` can't force an
+    // unbounded scan over the rest of the document. Scanned over bytes
+    // (rather than sliced at a byte offset) since a multibyte char in that
+    // window must not land us on a non-char-boundary index; `;` is ASCII and
+    // can never appear as part of a multibyte sequence, so a byte position
+    // where it matches is always a valid `str` index.
+    let max_digits = if hex { 6 } else { 7 };
+    let end = digits
+        .as_bytes()
+        .iter()
+        .take(max_digits + 1)
+        .position(|&b| b == b';')?;
+    let (num_str, radix) = (&digits[..end], if hex { 16 } else { 10 });
+    if num_str.is_empty()
+        || !num_str
+            .bytes()
+            .all(|b| if hex { b.is_ascii_hexdigit() } else { b.is_ascii_digit() })
+    {
+        return None;
+    }
+    let code = u32::from_str_radix(num_str, radix).ok()?;
+    let ch = char::from_u32(code)?;
+    let consumed = 1 + 1 + (hex as usize) + num_str.len() + 1;
+    Some((ch, consumed))
+}
+
+pub fn lut_unescape(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut idx = needs_unescape(bytes)?;
+    let mut out = String::with_capacity(input.len());
+    let mut prev_idx = 0;
+    while let Some(rel) = needs_unescape(&bytes[idx..]) {
+        idx += rel;
+        match decode_entity(&input[idx..]) {
+            Some((ch, consumed)) => {
+                out.push_str(&input[prev_idx..idx]);
+                out.push(ch);
+                idx += consumed;
+                prev_idx = idx;
+            }
+            None => {
+                idx += 1;
+            }
+        }
+    }
+    out.push_str(&input[prev_idx..]);
+    Some(out)
+}
+
 #[pyfunction]
 pub fn _escape_inner<'py>(
     py: Python<'py>,
@@ -83,16 +192,127 @@ pub fn _escape_inner<'py>(
     }
 }
 
+#[pyfunction]
+pub fn _unescape_inner<'py>(
+    py: Python<'py>,
+    s: Bound<'py, PyString>,
+) -> PyResult<Bound<'py, PyString>> {
+    if let Some(out) = lut_unescape(s.to_str()?) {
+        Ok(PyString::new_bound(py, out.as_str()))
+    } else {
+        Ok(s)
+    }
+}
+
+const ESCAPE_INTO_CHUNK_SIZE: usize = 64 * 1024;
+
+// Buffers `write_str` calls and flushes to the Python `out.write(...)` method
+// once the buffer reaches `ESCAPE_INTO_CHUNK_SIZE`, so `_escape_into` never
+// holds more than one chunk of escaped output in memory at a time.
+struct PyChunkedWriter<'py> {
+    out: Bound<'py, PyAny>,
+    buf: String,
+    // `std::fmt::Write` can't carry a `PyErr`, so when `out.write(...)` raises
+    // we stash it here and `_escape_into` re-raises it after the fact instead
+    // of a generic placeholder error.
+    write_err: Option<PyErr>,
+}
+
+impl<'py> PyChunkedWriter<'py> {
+    fn new(out: Bound<'py, PyAny>) -> Self {
+        Self {
+            out,
+            buf: String::with_capacity(ESCAPE_INTO_CHUNK_SIZE),
+            write_err: None,
+        }
+    }
+
+    fn flush(&mut self) -> PyResult<()> {
+        if !self.buf.is_empty() {
+            self.out.call_method1("write", (self.buf.as_str(),))?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Write for PyChunkedWriter<'_> {
+    fn write_str(&mut self, mut s: &str) -> std::fmt::Result {
+        // Split long runs at char boundaries so `buf` never grows past one chunk.
+        while self.buf.len() + s.len() >= ESCAPE_INTO_CHUNK_SIZE {
+            let mut take = ESCAPE_INTO_CHUNK_SIZE - self.buf.len();
+            while !s.is_char_boundary(take) {
+                take -= 1;
+            }
+            let (head, tail) = s.split_at(take);
+            self.buf.push_str(head);
+            s = tail;
+            if let Err(err) = self.flush() {
+                self.write_err = Some(err);
+                return Err(std::fmt::Error);
+            }
+        }
+        self.buf.push_str(s);
+        Ok(())
+    }
+}
+
+#[pyfunction]
+pub fn _escape_into<'py>(
+    _py: Python<'py>,
+    s: Bound<'py, PyString>,
+    out: Bound<'py, PyAny>,
+) -> PyResult<()> {
+    let mut writer = PyChunkedWriter::new(out);
+    if escape_to(s.to_str()?, &mut writer).is_err() {
+        return Err(writer
+            .write_err
+            .take()
+            .unwrap_or_else(|| PyRuntimeError::new_err("failed to write escaped output")));
+    }
+    writer.flush()
+}
+
+#[pyfunction]
+pub fn _escape_inner_batch<'py>(
+    py: Python<'py>,
+    strings: Bound<'py, PySequence>,
+) -> PyResult<Bound<'py, PyList>> {
+    let len = strings.len()?;
+    let mut originals = Vec::with_capacity(len);
+    let mut owned = Vec::with_capacity(len);
+    for item in strings.iter()? {
+        let s = item?.downcast_into::<PyString>()?;
+        owned.push(s.to_str()?.to_owned());
+        originals.push(s);
+    }
+
+    let escaped: Vec<Option<String>> =
+        py.allow_threads(|| owned.iter().map(|s| lut_replace(s.as_str())).collect());
+
+    let results = PyList::empty_bound(py);
+    for (s, out) in originals.into_iter().zip(escaped) {
+        match out {
+            Some(out) => results.append(PyString::new_bound(py, out.as_str()))?,
+            None => results.append(s)?,
+        }
+    }
+    Ok(results)
+}
+
 #[pymodule]
 #[pyo3(name = "_rust_speedups")]
 fn speedups<'py>(_py: Python<'py>, m: &Bound<'py, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(_escape_inner, m)?)?;
+    m.add_function(wrap_pyfunction!(_escape_into, m)?)?;
+    m.add_function(wrap_pyfunction!(_unescape_inner, m)?)?;
+    m.add_function(wrap_pyfunction!(_escape_inner_batch, m)?)?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::lut_replace;
+    use crate::{escape_to, lut_replace, lut_unescape};
 
     #[test]
     fn empty() {
@@ -159,4 +379,68 @@ mod tests {
             lut_replace("abcd&><'\"".repeat(1024).as_str()).unwrap()
         );
     }
+
+    #[test]
+    fn unescape_no_change() {
+        assert!(lut_unescape("abcdefgh").is_none());
+    }
+
+    #[test]
+    fn unescape_named_entities() {
+        assert_eq!(
+            "abcd&><'\"efgh",
+            lut_unescape("abcd&amp;&gt;&lt;&#39;&#34;efgh").unwrap()
+        );
+    }
+
+    #[test]
+    fn unescape_numeric_decimal() {
+        assert_eq!("a<b", lut_unescape("a&#60;b").unwrap());
+    }
+
+    #[test]
+    fn unescape_numeric_hex() {
+        assert_eq!("a<b", lut_unescape("a&#x3C;b").unwrap());
+        assert_eq!("a<b", lut_unescape("a&#X3c;b").unwrap());
+    }
+
+    #[test]
+    fn unescape_malformed_untouched() {
+        assert_eq!("a&foo b&c", lut_unescape("a&foo b&c").unwrap());
+        assert_eq!("a&#xzz;b", lut_unescape("a&#xzz;b").unwrap());
+        assert_eq!("a&amp b", lut_unescape("a&amp b").unwrap());
+    }
+
+    #[test]
+    fn unescape_numeric_boundary() {
+        assert_eq!("\u{10FFFF}", lut_unescape("&#1114111;").unwrap());
+        assert_eq!("\u{10FFFF}", lut_unescape("&#x10FFFF;").unwrap());
+        // No ';' within the longest possible code point: must not scan past
+        // it looking for one, and must leave the input untouched.
+        let unterminated = "&#".to_string() + &"1".repeat(4096);
+        assert_eq!(unterminated, lut_unescape(unterminated.as_str()).unwrap());
+    }
+
+    #[test]
+    fn unescape_numeric_multibyte_lookahead() {
+        // A multibyte char inside the bounded lookahead window must not
+        // cause a non-char-boundary slice.
+        assert_eq!("&#€€€", lut_unescape("&#€€€").unwrap());
+        assert_eq!("&#x€€€", lut_unescape("&#x€€€").unwrap());
+    }
+
+    #[test]
+    fn escape_to_matches_lut_replace() {
+        let inp = "abcd&><'\"efgh".repeat(1024);
+        let mut out = String::new();
+        escape_to(inp.as_str(), &mut out).unwrap();
+        assert_eq!(lut_replace(inp.as_str()).unwrap(), out);
+    }
+
+    #[test]
+    fn escape_to_no_change() {
+        let mut out = String::new();
+        escape_to("abcdefgh", &mut out).unwrap();
+        assert_eq!("abcdefgh", out);
+    }
 }